@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::io;
 use std::path::{Path, PathBuf};
 
@@ -26,8 +27,11 @@ impl Positions {
 	}
 }
 
-/// Blooms database.
-pub struct Database {
+/// Open file handles backing a `Database`.
+///
+/// Grouped together so they can be dropped and re-acquired as a unit by
+/// `Database::close`/`Database::reopen`.
+struct Handles {
 	/// Top level bloom file
 	///
 	/// Every bloom represents 16 blooms on mid level
@@ -44,6 +48,17 @@ pub struct Database {
 	///
 	/// Inserted blooms are always appended to this file
 	pending: Pending,
+}
+
+/// Returns the error used when a `Database` is accessed while closed.
+fn closed_error() -> io::Error {
+	io::Error::new(io::ErrorKind::Other, "database is closed, call `reopen` first")
+}
+
+/// Blooms database.
+pub struct Database {
+	/// Backing file handles, `None` while the database is closed.
+	handles: Option<Handles>,
 	/// Datbase directory
 	path: PathBuf,
 }
@@ -51,18 +66,37 @@ pub struct Database {
 impl Database {
 	/// Opens blooms database.
 	pub fn open<P>(path: P) -> io::Result<Database> where P: AsRef<Path> {
-		let path = path.as_ref();
-		let database = Database {
-			top: File::open(path.join("top.bdb"))?,
-			mid: File::open(path.join("mid.bdb"))?,
-			bot: File::open(path.join("bot.bdb"))?,
-			pending: Pending::open(path.join("pending.bdb"))?,
-			path: path.to_path_buf(),
+		let mut database = Database {
+			handles: None,
+			path: path.as_ref().to_path_buf(),
 		};
+		database.reopen()?;
+		Ok(database)
+	}
+
+	/// Closes all backing file handles, leaving the database in a closed state.
+	/// Call `reopen` to resume using it.
+	pub fn close(&mut self) {
+		self.handles = None;
+	}
 
-		match read_meta(path.join("meta.bdb")) {
+	/// Reopens `top.bdb`, `mid.bdb`, `bot.bdb` and `pending.bdb` from `self.path`
+	/// and re-runs the `meta.bdb`/`pending_hash` consistency check.
+	pub fn reopen(&mut self) -> io::Result<()> {
+		let top = File::open(self.path.join("top.bdb"))?;
+		let mid = File::open(self.path.join("mid.bdb"))?;
+		let bot = File::open(self.path.join("bot.bdb"))?;
+		let pending = Pending::open(self.path.join("pending.bdb"))?;
+
+		// Drain the pending records now so a half-written trailing record left by a
+		// crash is rejected here rather than silently replayed by a later `flush`.
+		for record in pending.iterator()? {
+			record?;
+		}
+
+		match read_meta(self.path.join("meta.bdb")) {
 			Ok(meta) => {
-				let pending_hash = database.pending.hash()?;
+				let pending_hash = pending.hash()?;
 				if pending_hash != meta.pending_hash {
 					return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed pending file"));
 				}
@@ -71,36 +105,58 @@ impl Database {
 			Err(err) => return Err(err),
 		}
 
-		Ok(database)
+		self.handles = Some(Handles { top, mid, bot, pending });
+		Ok(())
+	}
+
+	fn handles(&self) -> io::Result<&Handles> {
+		self.handles.as_ref().ok_or_else(closed_error)
+	}
+
+	fn handles_mut(&mut self) -> io::Result<&mut Handles> {
+		self.handles.as_mut().ok_or_else(closed_error)
 	}
 
 	/// Insert consecutive blooms into database starting with positon from.
 	pub fn insert_blooms<'a, B>(&'a mut self, from: u64, blooms: impl Iterator<Item = B>) -> io::Result<()>
 	where ethbloom::BloomRef<'a>: From<B> {
 		for (index, bloom) in (from..).into_iter().zip(blooms) {
-			self.pending.append(index, bloom)?;
+			self.handles_mut()?.pending.append(index, bloom)?;
 		}
-		self.pending.flush()?;
+		self.handles_mut()?.pending.flush()?;
 		self.flush_meta()
 	}
 
-	/// Flush pending blooms.
+	/// Flush pending blooms, coalescing repeated top/mid writes into one per touched position.
 	pub fn flush(&mut self) -> io::Result<()> {
-		for tuple in self.pending.iterator()? {
-			let (index, bloom) = tuple?;
-			let pos = Positions::from_index(index);
+		let mut top_acc: BTreeMap<u64, ethbloom::Bloom> = BTreeMap::new();
+		let mut mid_acc: BTreeMap<u64, ethbloom::Bloom> = BTreeMap::new();
+
+		let pending = self.handles()?.pending.iterator()?.collect::<io::Result<Vec<_>>>()?;
+		for (index, bloom) in pending {
+			let pos = Positions::from_index(index as u64);
 
 			// constant forks make lead to increased ration of false positives in bloom filters
 			// since we do not rebuild top or mid level, but we should not be worried about that
 			// most of the time events at block n(a) occur also on block n(b) or n+1(b)
-			self.top.accrue_bloom(pos.top, &bloom)?;
-			self.mid.accrue_bloom(pos.mid, &bloom)?;
-			self.bot.replace_bloom(pos.bot, &bloom)?;
+			top_acc.entry(pos.top).or_insert_with(ethbloom::Bloom::default).accrue_bloom(&bloom);
+			mid_acc.entry(pos.mid).or_insert_with(ethbloom::Bloom::default).accrue_bloom(&bloom);
+
+			self.handles_mut()?.bot.replace_bloom(pos.bot, &bloom)?;
+		}
+
+		for (pos, bloom) in top_acc {
+			self.handles_mut()?.top.accrue_bloom(pos, &bloom)?;
+		}
+		for (pos, bloom) in mid_acc {
+			self.handles_mut()?.mid.accrue_bloom(pos, &bloom)?;
 		}
-		self.top.flush()?;
-		self.mid.flush()?;
-		self.bot.flush()?;
-		self.pending.clear()?;
+
+		let handles = self.handles_mut()?;
+		handles.top.flush()?;
+		handles.mid.flush()?;
+		handles.bot.flush()?;
+		handles.pending.clear()?;
 		self.flush_meta()
 	}
 
@@ -109,11 +165,12 @@ impl Database {
 	where ethbloom::BloomRef<'a>: From<B> {
 		let index = from / 256 * 256;
 		let pos = Positions::from_index(index);
+		let handles = self.handles()?;
 
 		let iter = DatabaseIterator {
-			top: self.top.iterator_from(pos.top)?,
-			mid: self.mid.iterator_from(pos.mid)?,
-			bot: self.bot.iterator_from(pos.bot)?,
+			top: handles.top.iterator_from(pos.top)?,
+			mid: handles.mid.iterator_from(pos.mid)?,
+			bot: handles.bot.iterator_from(pos.bot)?,
 			state: IteratorState::Top,
 			from,
 			to,
@@ -124,10 +181,36 @@ impl Database {
 		Ok(iter)
 	}
 
+	/// Same as `iterate_matching`, but fans the search out over 256-index chunks
+	/// on the thread pool, preserving ascending order.
+	#[cfg(feature = "rayon")]
+	pub fn par_iterate_matching<'a, B>(&'a self, from: u64, to: u64, bloom: B) -> io::Result<Vec<u64>>
+	where ethbloom::BloomRef<'a>: From<B>, B: Copy + Send + Sync {
+		use rayon::prelude::*;
+
+		if from > to {
+			return Ok(Vec::new());
+		}
+
+		let first_chunk = from / 256;
+		let last_chunk = to / 256;
+
+		let segments: Vec<Vec<u64>> = (first_chunk..=last_chunk)
+			.into_par_iter()
+			.map(|chunk| {
+				let segment_from = ::std::cmp::max(from, chunk * 256);
+				let segment_to = ::std::cmp::min(to, chunk * 256 + 255);
+				self.iterate_matching(segment_from, segment_to, bloom)?.collect()
+			})
+			.collect::<io::Result<Vec<Vec<u64>>>>()?;
+
+		Ok(segments.into_iter().flatten().collect())
+	}
+
 	fn flush_meta(&self) -> io::Result<()> {
 		let meta = Meta {
 			version: VERSION,
-			pending_hash: self.pending.hash()?
+			pending_hash: self.handles()?.pending.hash()?
 		};
 
 		save_meta(self.path.join("meta.bdb"), &meta)
@@ -276,4 +359,32 @@ mod tests {
 		let matches = database.iterate_matching(256, 257, &Bloom::from(0x10)).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
 		assert_eq!(matches, vec![256, 257]);
 	}
+
+	#[test]
+	fn test_close_reopen() {
+		let tempdir = TempDir::new("").unwrap();
+		let mut database = Database::open(tempdir.path()).unwrap();
+		database.insert_blooms(0, vec![Bloom::from(0), Bloom::from(0x01)].iter()).unwrap();
+		database.flush().unwrap();
+
+		database.close();
+		assert!(database.flush().is_err());
+
+		database.reopen().unwrap();
+		let matches = database.iterate_matching(0, 1, &Bloom::from(0)).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+		assert_eq!(matches, vec![0, 1]);
+	}
+
+	#[cfg(feature = "rayon")]
+	#[test]
+	fn test_par_iterate_matching() {
+		let tempdir = TempDir::new("").unwrap();
+		let mut database = Database::open(tempdir.path()).unwrap();
+		database.insert_blooms(254, vec![Bloom::from(0x100), Bloom::from(0x01), Bloom::from(0x10), Bloom::from(0x11)].iter()).unwrap();
+		database.flush().unwrap();
+
+		let sequential = database.iterate_matching(0, 600, &Bloom::from(0x01)).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+		let parallel = database.par_iterate_matching(0, 600, &Bloom::from(0x01)).unwrap();
+		assert_eq!(sequential, parallel);
+	}
 }
@@ -1,14 +1,22 @@
-use std::io::{Seek, SeekFrom, Write, Read};
 use std::path::Path;
 use std::{io, fs};
+use memmap2::{MmapMut, MmapOptions};
 use {ethbloom};
 
-/// Autoresizable file containing blooms.
+/// Capacity an empty backing file/mmap is first grown to; later growth doubles the current capacity.
+const MIN_CAPACITY: u64 = 4096;
+
+/// Autoresizable, memory-mapped file containing blooms.
 pub struct File {
 	/// Backing file.
 	file: fs::File,
-	/// Current file len.
+	/// Logical length written so far; bounds all reads.
 	len: u64,
+	/// Size of the backing file/map, grown geometrically ahead of `len` so that
+	/// appending consecutive blooms doesn't remap on every single write.
+	capacity: u64,
+	/// Memory map of the backing file, `None` while the capacity is zero.
+	mmap: Option<MmapMut>,
 }
 
 impl File {
@@ -18,85 +26,143 @@ impl File {
 			.read(true)
 			.write(true)
 			.create(true)
-			.append(true)
 			.open(path)?;
 		let len = file.metadata()?.len();
+		let mmap = Self::map(&file, len)?;
 
-		let file = File {
+		Ok(File {
 			file,
 			len,
-		};
+			capacity: len,
+			mmap,
+		})
+	}
+
+	/// Maps `len` bytes of `file`. Mapping a zero-length file is invalid, so an
+	/// empty file maps to `None` instead.
+	fn map(file: &fs::File, len: u64) -> io::Result<Option<MmapMut>> {
+		if len == 0 {
+			return Ok(None);
+		}
 
-		Ok(file)
+		let mmap = unsafe { MmapOptions::new().len(len as usize).map_mut(file)? };
+		Ok(Some(mmap))
+	}
 
+	/// Smallest capacity, doubling from `current` (or `MIN_CAPACITY`), that fits `required`.
+	fn grow_capacity(current: u64, required: u64) -> u64 {
+		let mut capacity = if current == 0 { MIN_CAPACITY } else { current };
+		while capacity < required {
+			capacity *= 2;
+		}
+		capacity
 	}
 
-	/// Resizes the file if there is not enough space to write bloom at given position.
+	/// Grows the backing capacity and remaps it if there is not enough space to
+	/// write bloom at given position. Capacity is grown geometrically, so
+	/// consecutive writes amortize into occasional remaps instead of one per write.
 	fn ensure_space_for_write(&mut self, pos: u64) -> io::Result<()> {
 		// position to write + 256 bytes
 		let required_space = (pos + 1) * 256;
+		if required_space > self.capacity {
+			let capacity = Self::grow_capacity(self.capacity, required_space);
+			self.file.set_len(capacity)?;
+			// Only commit the grown capacity once the remap actually succeeds, so a
+			// failed remap leaves us retrying instead of permanently believing the
+			// larger map is already in place.
+			self.mmap = Self::map(&self.file, capacity)?;
+			self.capacity = capacity;
+		}
 		if required_space > self.len {
-			self.file.set_len(required_space)?;
 			self.len = required_space;
 		}
 		Ok(())
 	}
 
+	fn mmap(&self) -> io::Result<&MmapMut> {
+		self.mmap.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "bloom file is empty"))
+	}
+
+	fn mmap_mut(&mut self) -> io::Result<&mut MmapMut> {
+		self.mmap.as_mut().ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "bloom file is empty"))
+	}
+
 	/// Read bloom at given position.
 	pub fn read_bloom(&self, pos: u64) -> io::Result<ethbloom::Bloom> {
-		let mut file_ref = &self.file;
-		file_ref.seek(SeekFrom::Start(pos * 256))?;
+		let start = pos as usize * 256;
+		if start as u64 + 256 > self.len {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "bloom position out of range"));
+		}
 		let mut bloom = ethbloom::Bloom::default();
-		file_ref.read_exact(&mut bloom)?;
+		bloom.copy_from_slice(&self.mmap()?[start..start + 256]);
 		Ok(bloom)
 	}
 
 	/// Accrue bloom into bloom at given position.
 	pub fn accrue_bloom<'a, B>(&mut self, pos: u64, bloom: B) -> io::Result<()> where ethbloom::BloomRef<'a>: From<B> {
 		self.ensure_space_for_write(pos)?;
-		let mut old_bloom: ethbloom::Bloom = self.read_bloom(pos)?;
+		let mut old_bloom = self.read_bloom(pos)?;
 		old_bloom.accrue_bloom(bloom);
-		let mut file_ref = &self.file;
-		file_ref.seek(SeekFrom::Start(pos * 256))?;
-		file_ref.write_all(&old_bloom)
+		let start = pos as usize * 256;
+		self.mmap_mut()?[start..start + 256].copy_from_slice(&old_bloom);
+		Ok(())
 	}
 
 	/// Replace bloom at given position with a new one.
 	pub fn replace_bloom<'a, B>(&mut self, pos: u64, bloom: B) -> io::Result<()> where ethbloom::BloomRef<'a>: From<B> {
 		self.ensure_space_for_write(pos)?;
-		let mut file_ref = &self.file;
-		file_ref.seek(SeekFrom::Start(pos * 256))?;
-		file_ref.write_all(ethbloom::BloomRef::from(bloom).data())
+		let start = pos as usize * 256;
+		let data = ethbloom::BloomRef::from(bloom).data();
+		self.mmap_mut()?[start..start + 256].copy_from_slice(data);
+		Ok(())
 	}
 
-	/// Returns an iterator over file.
+	/// Returns an iterator over file, starting at position `0`.
 	pub fn iterator(&self) -> io::Result<FileIterator> {
-		let mut file_ref = &self.file;
-		file_ref.seek(SeekFrom::Start(0))?;
-
-		let iter = FileIterator {
-			file: file_ref,
-		};
+		self.iterator_from(0)
+	}
 
-		Ok(iter)
+	/// Returns an iterator over file, starting at the given bloom position.
+	pub fn iterator_from(&self, pos: u64) -> io::Result<FileIterator> {
+		Ok(FileIterator {
+			mmap: self.mmap.as_ref(),
+			pos: pos as usize * 256,
+			len: self.len as usize,
+		})
 	}
 
-	/// Flush outstanding modifications to the disk
+	/// Flush outstanding modifications to the disk, trimming away any capacity
+	/// grown ahead of `len` so the file's on-disk size reflects real data again.
 	pub fn flush(&mut self) -> io::Result<()> {
+		if let Some(ref mmap) = self.mmap {
+			mmap.flush()?;
+		}
+		if self.capacity > self.len {
+			self.file.set_len(self.len)?;
+			self.mmap = Self::map(&self.file, self.len)?;
+			self.capacity = self.len;
+		}
 		self.file.flush()
 	}
 }
 
 /// Iterator over blooms of a single file.
+///
+/// Walks the file's memory map directly, so advancing or reading never issues
+/// a syscall once the map is in place.
 pub struct FileIterator<'a> {
-	/// Backing file.
-	file: &'a fs::File,
+	/// Backing memory map, `None` while the underlying file is empty.
+	mmap: Option<&'a MmapMut>,
+	/// Current byte offset into the map.
+	pos: usize,
+	/// Logical length of the file; bounds iteration past any grown-ahead capacity.
+	len: usize,
 }
 
 impl<'a> FileIterator<'a> {
 	/// Advance file by n blooms
 	pub fn advance(&mut self, n: u64) -> io::Result<()> {
-		self.file.seek(SeekFrom::Current(n as i64 * 256))?;
+		self.pos += n as usize * 256;
 		Ok(())
 	}
 }
@@ -105,12 +171,13 @@ impl<'a> Iterator for FileIterator<'a> {
 	type Item = io::Result<ethbloom::Bloom>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		let mut bloom = ethbloom::Bloom::default();
-		match self.file.read_exact(&mut bloom) {
-			Ok(_) => Some(Ok(bloom)),
-			Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
-			Err(err) => Some(Err(err)),
+		if self.pos + 256 > self.len {
+			return None;
 		}
+		let slice = self.mmap?.get(self.pos..self.pos + 256)?;
+		let mut bloom = ethbloom::Bloom::default();
+		bloom.copy_from_slice(slice);
+		self.pos += 256;
+		Some(Ok(bloom))
 	}
 }
-
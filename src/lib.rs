@@ -4,7 +4,12 @@
 //! zero copying
 
 extern crate byteorder;
+extern crate crc32fast;
 extern crate ethbloom;
+extern crate memmap2;
+
+#[cfg(feature = "rayon")]
+extern crate rayon;
 
 #[cfg(test)]
 extern crate tempdir;
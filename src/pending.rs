@@ -1,16 +1,30 @@
 use std::{fs, io};
 use std::io::{Seek, SeekFrom, Write, Read};
 use byteorder::{WriteBytesExt, ReadBytesExt, LittleEndian};
+use crc32fast;
 use ethbloom;
 
+/// Bytes of an `(index, bloom)` tuple, not counting its trailing CRC.
+const PAYLOAD_LEN: usize = 8 + 256;
+/// Bytes of a full record: the payload plus its CRC32.
+const RECORD_LEN: usize = PAYLOAD_LEN + 4;
+
 pub struct Pending {
 	file: fs::File,
 }
 
 impl Pending {
+	/// Appends an `(index, bloom)` tuple, framed with a CRC32 of the payload so
+	/// a torn write left behind by a crash can be told apart from a clean file.
 	pub fn append<'a, B>(&mut self, index: usize, bloom: B) -> io::Result<()> where ethbloom::BloomRef<'a>: From<B> {
-		self.file.write_u64::<LittleEndian>(index as u64)?;
-		self.file.write_all(ethbloom::BloomRef::from(bloom).data())
+		let bloom = ethbloom::BloomRef::from(bloom);
+		let mut payload = Vec::with_capacity(PAYLOAD_LEN);
+		payload.write_u64::<LittleEndian>(index as u64)?;
+		payload.write_all(bloom.data())?;
+		let crc = crc32fast::hash(&payload);
+
+		self.file.write_all(&payload)?;
+		self.file.write_u32::<LittleEndian>(crc)
 	}
 
 	pub fn flush(&mut self) -> io::Result<()> {
@@ -37,19 +51,110 @@ pub struct PendingIterator<'a> {
 	file: &'a fs::File,
 }
 
+impl<'a> PendingIterator<'a> {
+	/// Reads the next record's raw bytes, or `None` at a clean end of file.
+	fn read_record(&mut self) -> io::Result<Option<[u8; RECORD_LEN]>> {
+		let mut record = [0u8; RECORD_LEN];
+		let mut read = 0;
+		while read < RECORD_LEN {
+			match self.file.read(&mut record[read..])? {
+				0 => break,
+				n => read += n,
+			}
+		}
+
+		if read == 0 {
+			Ok(None)
+		} else if read == RECORD_LEN {
+			Ok(Some(record))
+		} else {
+			Err(io::Error::new(io::ErrorKind::UnexpectedEof, "pending file ends with a truncated record"))
+		}
+	}
+}
+
 impl<'a> Iterator for PendingIterator<'a> {
 	type Item = io::Result<(usize, ethbloom::Bloom)>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		let index = match self.file.read_u64::<LittleEndian>() {
-			Ok(index) => index,
-			Err(_) => return None,
+		let record = match self.read_record() {
+			Ok(Some(record)) => record,
+			Ok(None) => return None,
+			Err(err) => return Some(Err(err)),
 		};
 
-		let mut bloom = ethbloom::Bloom::default();
-		match self.file.read_exact(&mut bloom) {
-			Ok(_) => Some(Ok((index as usize, bloom))),
-			Err(err) => Some(Err(err)),
+		let payload = &record[..PAYLOAD_LEN];
+		let crc = (&record[PAYLOAD_LEN..]).read_u32::<LittleEndian>().expect("record is exactly RECORD_LEN bytes long");
+		if crc32fast::hash(payload) != crc {
+			return Some(Err(io::Error::new(io::ErrorKind::InvalidData, "pending record failed its CRC32 check")));
 		}
+
+		let index = (&payload[..8]).read_u64::<LittleEndian>().expect("payload is exactly PAYLOAD_LEN bytes long");
+		let mut bloom = ethbloom::Bloom::default();
+		bloom.copy_from_slice(&payload[8..]);
+		Some(Ok((index as usize, bloom)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::io;
+	use std::io::{Seek, SeekFrom, Write};
+	use std::path::Path;
+	use ethbloom::Bloom;
+	use tempdir::TempDir;
+	use super::Pending;
+
+	fn open(path: &Path) -> Pending {
+		let file = fs::OpenOptions::new().read(true).write(true).create(true).append(true).open(path).unwrap();
+		Pending { file }
+	}
+
+	#[test]
+	fn test_append_and_iterate_round_trips() {
+		let tempdir = TempDir::new("").unwrap();
+		let mut pending = open(&tempdir.path().join("pending.bdb"));
+		pending.append(0, &Bloom::from(0x01)).unwrap();
+		pending.append(1, &Bloom::from(0x10)).unwrap();
+		pending.flush().unwrap();
+
+		let records = pending.iterator().unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+		assert_eq!(records, vec![(0, Bloom::from(0x01)), (1, Bloom::from(0x10))]);
+	}
+
+	#[test]
+	fn test_truncated_trailing_record_is_detected() {
+		let tempdir = TempDir::new("").unwrap();
+		let mut pending = open(&tempdir.path().join("pending.bdb"));
+		pending.append(0, &Bloom::from(0x01)).unwrap();
+		// Simulate a crash partway through writing the next record.
+		pending.file.write_all(&[0u8; 10]).unwrap();
+		pending.flush().unwrap();
+
+		let mut iter = pending.iterator().unwrap();
+		assert_eq!(iter.next().unwrap().unwrap(), (0, Bloom::from(0x01)));
+		let err = iter.next().unwrap().unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+		assert!(iter.next().is_none());
+	}
+
+	#[test]
+	fn test_corrupted_record_is_detected() {
+		let tempdir = TempDir::new("").unwrap();
+		let path = tempdir.path().join("pending.bdb");
+		let mut pending = open(&path);
+		pending.append(0, &Bloom::from(0x01)).unwrap();
+		pending.flush().unwrap();
+
+		// Flip a payload byte without touching its CRC.
+		let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+		file.seek(SeekFrom::Start(0)).unwrap();
+		file.write_all(&[0xff]).unwrap();
+		file.sync_all().unwrap();
+
+		let mut iter = pending.iterator().unwrap();
+		let err = iter.next().unwrap().unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
 	}
 }